@@ -0,0 +1,25 @@
+/// Signature for a pluggable checksum function: raw serialized bytes in, a
+/// wide digest out. [`RollbackSnapshot::checksum`](crate::snapshot::RollbackSnapshot::checksum)
+/// impls are free to use their own, but [`DEFAULT_CHECKSUM`] is what
+/// [`RapierSnapshot`](crate::snapshot::RapierSnapshot) uses.
+pub type ChecksumFn = fn(&[u8]) -> u64;
+
+/// Replaces the old 16-bit `fletcher16` checksum. With only 65k buckets,
+/// two genuinely divergent physics states collide roughly once every few
+/// thousand frames, silently passing desync detection. 64 bits makes that
+/// astronomically unlikely while staying a cheap single-pass hash with no
+/// allocation, so we keep paying it every frame.
+pub const DEFAULT_CHECKSUM: ChecksumFn = fnv1a64;
+
+/// FNV-1a, 64-bit variant.
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET_BASIS;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
@@ -0,0 +1,136 @@
+use bevy_matchbox::prelude::{MatchboxSocket, MultipleChannels, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Channel index this traffic rides on, separate from GGRS's own input
+/// channel (channel 0) so a big batch of hashes never head-of-line blocks
+/// an input packet.
+pub const DESYNC_CHANNEL: usize = 1;
+
+/// A batch of confirmed Rapier checksums sent over a dedicated reliable
+/// channel, decoupled from the bitpacked [`GGRSInput`](crate::rollback::GGRSInput)
+/// payload.
+///
+/// This used to piggyback on `GGRSInput::last_confirmed_hash`/
+/// `last_confirmed_frame`, one hash per input message. That capped us at a
+/// single outstanding hash per tick and required the awkward "find an
+/// unsent hash" loop in `input`. Batching them here instead means a client
+/// can catch its peer up on several frames at once, converging on desync
+/// detection faster, and input no longer has to carry anything but input.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DesyncHashBatch {
+    pub hashes: Vec<(Frame, u64)>,
+}
+
+/// Outgoing batches queued for the next reliable send. Drained onto
+/// [`DESYNC_CHANNEL`] by [`flush_outgoing_desync_hashes`].
+#[derive(Default, Resource)]
+pub struct OutgoingDesyncHashes(pub Vec<DesyncHashBatch>);
+
+/// Incoming batches received from peers since the last time they were
+/// drained by [`receive_desync_hashes`]. Filled in from [`DESYNC_CHANNEL`]
+/// by [`poll_desync_hashes`].
+#[derive(Default, Resource)]
+pub struct IncomingDesyncHashes(pub Vec<DesyncHashBatch>);
+
+/// Gathers every confirmed-but-unsent hash in [`FrameHashes`] into a single
+/// batch and queues it on the reliable side channel. Mirrors the loop that
+/// used to live in `input`, minus having to shoehorn the result into a
+/// `GGRSInput`.
+pub fn send_desync_hashes(
+    mut hashes: ResMut<FrameHashes>,
+    validatable_frame: Res<ValidatableFrame>,
+    mut outgoing: ResMut<OutgoingDesyncHashes>,
+) {
+    let mut batch = DesyncHashBatch::default();
+
+    // Same "due diligence" reasoning as before: inform our opponent of every
+    // hash we have, since we might as well, and we need to determine a
+    // desync *eventually* or the match is pretty much invalidated anyway.
+    for frame_hash in hashes.0.iter_mut() {
+        if frame_hash.confirmed
+            && !frame_hash.sent
+            && validatable_frame.is_validatable(frame_hash.frame)
+        {
+            info!("Queuing desync hash {:?}", frame_hash);
+            batch
+                .hashes
+                .push((frame_hash.frame, frame_hash.rapier_checksum));
+            frame_hash.sent = true;
+        }
+    }
+
+    if !batch.hashes.is_empty() {
+        outgoing.0.push(batch);
+    }
+}
+
+/// Applies every batch received since the last tick to [`RxFrameHashes`].
+/// Replaces the inline handling that used to live at the top of
+/// `apply_inputs`.
+pub fn receive_desync_hashes(
+    mut incoming: ResMut<IncomingDesyncHashes>,
+    mut hashes: ResMut<RxFrameHashes>,
+) {
+    for batch in incoming.0.drain(..) {
+        for (frame, checksum) in batch.hashes {
+            log::info!("Got desync hash data for frame {} : {}", frame, checksum);
+            if let Some(frame_hash) = hashes.0.get_mut((frame as usize) % DESYNC_MAX_FRAMES) {
+                assert!(
+                    frame_hash.frame != frame || frame_hash.rapier_checksum == checksum,
+                    "Got new data for existing frame data {}",
+                    frame_hash.frame
+                );
+
+                // Only update this local data if the frame is new-to-us.
+                // We don't want to overwrite any existing validated status
+                // unless the frame is replacing what is already in the buffer.
+                if frame_hash.frame != frame {
+                    frame_hash.frame = frame;
+                    frame_hash.rapier_checksum = checksum;
+                    frame_hash.validated = false;
+                }
+            }
+        }
+    }
+}
+
+/// Sends every batch queued in [`OutgoingDesyncHashes`] to all connected
+/// peers over [`DESYNC_CHANNEL`]. This is the part that was missing before:
+/// batches were being built and queued, but nothing ever put them on the
+/// wire, so desync detection never actually ran against a peer.
+pub fn flush_outgoing_desync_hashes(
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut outgoing: ResMut<OutgoingDesyncHashes>,
+) {
+    if outgoing.0.is_empty() {
+        return;
+    }
+
+    let peers: Vec<PeerId> = socket.connected_peers().collect();
+    for batch in outgoing.0.drain(..) {
+        let Ok(packet) = bincode::serialize(&batch) else {
+            continue;
+        };
+        for peer in &peers {
+            socket
+                .channel_mut(DESYNC_CHANNEL)
+                .send(packet.clone().into_boxed_slice(), *peer);
+        }
+    }
+}
+
+/// Reads every packet waiting on [`DESYNC_CHANNEL`] and queues it onto
+/// [`IncomingDesyncHashes`] for [`receive_desync_hashes`] to apply.
+pub fn poll_desync_hashes(
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut incoming: ResMut<IncomingDesyncHashes>,
+) {
+    for (_peer, packet) in socket.channel_mut(DESYNC_CHANNEL).receive() {
+        let Ok(batch) = bincode::deserialize::<DesyncHashBatch>(&packet) else {
+            continue;
+        };
+        incoming.0.push(batch);
+    }
+}
@@ -1,12 +1,25 @@
+use bevy::ecs::world::Mut;
+
 use crate::prelude::*;
+use crate::snapshot::RollbackSnapshots;
+use crate::sync_test::ChecksumHistory;
 
 /// Our physics rollback state container, which will be rolled back and we will
 /// use to restore our physics state.
+///
+/// `snapshot_bytes` has to live here, not in some other resource, because
+/// this is the type bevy_ggrs actually snapshots and restores per-frame via
+/// `Reflect`. A resource that isn't reflected and registered the same way
+/// only ever holds whatever was last written to it, so on a rollback to
+/// frame F it would hand back the latest frame's bytes instead of frame F's
+/// — silently restoring the wrong state.
 #[derive(Default, Reflect, Hash, Resource, PartialEq, Eq)]
 #[reflect(Hash, Resource, PartialEq)]
 pub struct PhysicsRollbackState {
-    pub rapier_state: Option<Vec<u8>>,
-    pub rapier_checksum: u16,
+    pub rapier_checksum: u64,
+    /// Bytes last saved for each entry in [`RollbackSnapshots`], indexed
+    /// the same way.
+    pub snapshot_bytes: Vec<Vec<u8>>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Resource, Hash, Reflect)]
@@ -71,112 +84,86 @@ pub fn toggle_physics(
     config.physics_pipeline_active = physics_enabled.0;
 }
 
-pub fn rollback_rapier_context(
-    rollback_status: Res<RollbackStatus>,
-    game_state: Res<PhysicsRollbackState>,
-    mut rapier: ResMut<RapierContext>,
-) {
-    let mut checksum = game_state.rapier_checksum;
-    log::info!("Context pre-hash at start: {:?}", checksum);
-
-    // Serialize our physics state for hashing, to display the state in-flight.
-    // This should not be necessary for this demo to work, as we will do the
-    // real checksum during `save_game_state` at the end of the pipeline.
-    if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-        checksum = fletcher16(&context_bytes);
-        log::info!("Context hash at start: {}", checksum);
-    }
+pub fn rollback_rapier_context(world: &mut World) {
+    let rollback_status = world.resource::<RollbackStatus>();
+    let should_restore = rollback_status.is_rollback && rollback_status.rollback_frame > 1;
 
     // Only restore our state if we are in a rollback.  This step is *critical*.
     // Only doing this during rollbacks saves us a step every frame.  Here, we
     // also do not allow rollback to frame 0.  Physics state is already correct
     // in this case.  This prevents lagged clients from getting immediate desync
     // and is entirely a hack since we don't enable physics until later anyway.
-    //
-    // You can also test that desync detection is working by disabling:
-    // if false {
-    if rollback_status.is_rollback && rollback_status.rollback_frame > 1 {
-        if let Some(state_context) = game_state.rapier_state.as_ref() {
-            if let Ok(context) = bincode::deserialize::<RapierContext>(state_context) {
-                // commands.insert_resource(context);
-                // *rapier = context;
-
-                // Inserting or replacing directly seems to screw up some of the
-                // crate-only properties.  So, we'll copy over each public
-                // property instead.
-                rapier.bodies = context.bodies;
-                rapier.broad_phase = context.broad_phase;
-                rapier.ccd_solver = context.ccd_solver;
-                rapier.colliders = context.colliders;
-                rapier.impulse_joints = context.impulse_joints;
-                rapier.integration_parameters = context.integration_parameters;
-                rapier.islands = context.islands;
-                rapier.multibody_joints = context.multibody_joints;
-                rapier.narrow_phase = context.narrow_phase;
-                rapier.query_pipeline = context.query_pipeline;
-
-                // pipeline is not serialized
-                // rapier.pipeline = context.pipeline;
-            }
-        }
+    if !should_restore {
+        return;
+    }
 
-        // Again, not necessary for the demo, just to show the rollback changes
-        // as they occur.
-        if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-            log::info!(
-                "Context hash after rollback: {}",
-                fletcher16(&context_bytes)
-            );
+    world.resource_scope(|world, mut snapshots: Mut<RollbackSnapshots>| {
+        let saved = world.resource::<PhysicsRollbackState>().snapshot_bytes.clone();
+        for (snapshot, bytes) in snapshots.0.iter_mut().zip(saved.iter()) {
+            snapshot.restore(world, bytes);
         }
-    }
+    });
+
+    log::info!("Context restored from saved snapshots after rollback");
 }
 
-pub fn save_rapier_context(
-    mut game_state: ResMut<PhysicsRollbackState>,
-    rapier: Res<RapierContext>,
-    mut hashes: ResMut<FrameHashes>,
-    confirmed_frame: Res<ConfirmedFrame>,
-    current_frame: Res<CurrentFrame>,
-) {
-    // This serializes our context every frame.  It's not great, but works to
-    // integrate the two plugins.  To do less of it, we would need to change
-    // bevy_ggrs to serialize arbitrary structs like this one in addition to
-    // component tracking.  If you need this to happen less, I'd recommend not
-    // using the plugin and implementing GGRS yourself.
-    if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-        log::info!("Context hash before save: {}", game_state.rapier_checksum);
-        game_state.rapier_checksum = fletcher16(&context_bytes);
-        game_state.rapier_state = Some(context_bytes);
-        log::info!("Context hash after save: {}", game_state.rapier_checksum);
-
-        if let Some(frame_hash) = hashes
-            .0
-            .get_mut((current_frame.0 as usize) % DESYNC_MAX_FRAMES)
-        {
-            if frame_hash.frame == current_frame.0 && frame_hash.sent {
-                // If this frame hash has already been sent and its the
-                // same one then the hashes better damn well match
-                assert_eq!(
-                    frame_hash.rapier_checksum, game_state.rapier_checksum,
-                    "INTEGRITY BREACHED"
-                );
-                log::info!(
-                    "Integrity challenged of frame {}: {} vs {}",
-                    frame_hash.frame,
-                    frame_hash.rapier_checksum,
-                    game_state.rapier_checksum
-                );
-            }
-
-            frame_hash.frame = current_frame.0;
-            frame_hash.rapier_checksum = game_state.rapier_checksum;
-            frame_hash.sent = false;
-            frame_hash.validated = false;
-            log::debug!("confirmed frame: {:?}", confirmed_frame);
-            frame_hash.confirmed = frame_hash.frame <= confirmed_frame.0;
-            log::debug!("Stored frame hash at save: {:?}", frame_hash);
+pub fn save_rapier_context(world: &mut World) {
+    // This serializes all registered snapshots every frame.  It's not great,
+    // but works to integrate the two plugins.  To do less of it, we would
+    // need to change bevy_ggrs to serialize arbitrary structs like these in
+    // addition to component tracking.  If you need this to happen less, I'd
+    // recommend not using the plugin and implementing GGRS yourself.
+    let mut saved_bytes = Vec::new();
+    let mut checksum: u64 = 0;
+    world.resource_scope(|world, mut snapshots: Mut<RollbackSnapshots>| {
+        for snapshot in snapshots.0.iter_mut() {
+            let bytes = snapshot.save(world);
+            checksum ^= snapshot.checksum(&bytes);
+            saved_bytes.push(bytes);
+        }
+    });
+
+    let current_frame = world.resource::<CurrentFrame>().0;
+    let confirmed_frame = world.resource::<ConfirmedFrame>().0;
+
+    let mut game_state = world.resource_mut::<PhysicsRollbackState>();
+    log::info!("Context hash before save: {}", game_state.rapier_checksum);
+    game_state.rapier_checksum = checksum;
+    game_state.snapshot_bytes = saved_bytes;
+    log::info!("Context hash after save: {}", game_state.rapier_checksum);
+
+    // Feed the SyncTest determinism harness. This runs on every save, not
+    // only in SyncTest mode, and costs a hashmap insert plus an eviction
+    // pass over the history.
+    world.resource_scope(|_world, mut checksum_history: Mut<ChecksumHistory>| {
+        checksum_history.record(current_frame, checksum);
+    });
+
+    let mut hashes = world.resource_mut::<FrameHashes>();
+    if let Some(frame_hash) = hashes.0.get_mut((current_frame as usize) % DESYNC_MAX_FRAMES) {
+        if frame_hash.frame == current_frame && frame_hash.sent {
+            // If this frame hash has already been sent and its the
+            // same one then the hashes better damn well match
+            assert_eq!(
+                frame_hash.rapier_checksum, checksum,
+                "INTEGRITY BREACHED"
+            );
+            log::info!(
+                "Integrity challenged of frame {}: {} vs {}",
+                frame_hash.frame,
+                frame_hash.rapier_checksum,
+                checksum
+            );
         }
 
-        log::info!("----- end frame {} -----", current_frame.0);
+        frame_hash.frame = current_frame;
+        frame_hash.rapier_checksum = checksum;
+        frame_hash.sent = false;
+        frame_hash.validated = false;
+        log::debug!("confirmed frame: {:?}", confirmed_frame);
+        frame_hash.confirmed = frame_hash.frame <= confirmed_frame;
+        log::debug!("Stored frame hash at save: {:?}", frame_hash);
     }
+
+    log::info!("----- end frame {} -----", current_frame);
 }
@@ -3,6 +3,7 @@ use bevy_matchbox::prelude::PeerId;
 use ggrs::Config;
 
 use crate::prelude::*;
+use crate::sync_test::SyncTestRng;
 
 // These are just 16 bit for bit-packing alignment in the input struct
 const INPUT_UP: u16 = 0b00001;
@@ -46,21 +47,10 @@ pub struct GGRSInput {
 
     pub mouse_x: i32,
     pub mouse_y: i32,
-
-    // Desync detection
-    pub last_confirmed_hash: u16,
-    _padding3: u16, // Keep things 32-bit-aligned for Bytemuck
-
-    pub last_confirmed_frame: Frame,
-    // Ok, so I know what you're thinking:
-    // > "That's not input!"
-    // Well, you're right, and we're going to abuse the existing socket to also
-    // communicate about the last confirmed frame we saw and what was the hash
-    // of the physics state.  This allows us to detect desync.  This could also
-    // use a new socket, but who wants to hole punch twice?  I have been working
-    // on a GGRS branch (linked below) that introduces a new message type, but
-    // it is not ready.  However, input-packing works good enough for now.
-    // https://github.com/cscorley/ggrs/tree/arbitrary-messages-0.8
+    // Desync detection hashes used to live here, piggybacked onto every
+    // input payload. They now travel over a dedicated reliable channel (see
+    // `desync::send_desync_hashes`/`desync::receive_desync_hashes`) so this
+    // struct is free to just be input again.
 }
 
 pub fn input(
@@ -68,37 +58,16 @@ pub fn input(
     local_handles: Res<LocalHandles>,
     keyboard_input: Res<Input<KeyCode>>,
     mut random: ResMut<RandomInput>,
+    mut sync_rng: ResMut<SyncTestRng>,
     physics_enabled: Res<PhysicsEnabled>,
-    mut hashes: ResMut<FrameHashes>,
-    validatable_frame: Res<ValidatableFrame>,
     window: Query<&Window, With<PrimaryWindow>>,
     camera: Query<(&Camera, &GlobalTransform)>,
     mouse_buttons: Res<Input<MouseButton>>,
 ) -> GGRSInput {
-    let mut input = GGRSInput {
-        last_confirmed_frame: ggrs::NULL_FRAME,
-        ..default()
-    };
+    let mut input = GGRSInput::default();
 
-    // Find a hash that we haven't sent yet.
-    // This probably seems like overkill but we have to track a bunch anyway, we
-    // might as well do our due diligence and inform our opponent of every hash
-    // we have This may mean we ship them out of order.  The important thing is
-    // we determine the desync *eventually* because that match is pretty much
-    // invalidated without a state synchronization mechanism (which GGRS/GGPO
-    // does not have out of the box.)
-    for frame_hash in hashes.0.iter_mut() {
-        // only send confirmed frames that have not yet been sent that are well past our max prediction window
-        if frame_hash.confirmed
-            && !frame_hash.sent
-            && validatable_frame.is_validatable(frame_hash.frame)
-        {
-            info!("Sending data {:?}", frame_hash);
-            input.last_confirmed_frame = frame_hash.frame;
-            input.last_confirmed_hash = frame_hash.rapier_checksum;
-            frame_hash.sent = true;
-        }
-    }
+    // Desync hashes no longer ride along with input; see
+    // `desync::send_desync_hashes` for that side channel.
 
     // Do not do anything until physics are live
     if !physics_enabled.0 {
@@ -123,10 +92,11 @@ pub fn input(
     if input.input != 0 && random.on && local_handles.handles.contains(&handle.0) {
         random.on = false;
     } else if input.input == 0 && random.on && local_handles.handles.contains(&handle.0) {
-        let mut rng = thread_rng();
         // Return a random input sometimes, or maybe nothing.
-        // Helps to trigger input-based rollbacks from the unplayed side
-        match rng.gen_range(0..10) {
+        // Helps to trigger input-based rollbacks from the unplayed side.
+        // Uses the seeded SyncTestRng rather than thread_rng() so a match
+        // stays reproducible from its seed.
+        match sync_rng.0.gen_range(0..10) {
             0 => input.input = INPUT_UP,
             1 => input.input = INPUT_LEFT,
             2 => input.input = INPUT_DOWN,
@@ -159,37 +129,10 @@ pub fn input(
 pub fn apply_inputs(
     mut query: Query<(&mut Velocity, &Player)>,
     inputs: Res<PlayerInputs<GGRSConfig>>,
-    mut hashes: ResMut<RxFrameHashes>,
-    local_handles: Res<LocalHandles>,
     physics_enabled: Res<PhysicsEnabled>,
 ) {
     for (mut v, p) in query.iter_mut() {
         let (game_input, input_status) = inputs[p.handle];
-        // Check the desync for this player if they're not a local handle
-        // Did they send us some goodies?
-        if !local_handles.handles.contains(&p.handle) && game_input.last_confirmed_frame > 0 {
-            log::info!("Got frame data {:?}", game_input);
-            if let Some(frame_hash) = hashes
-                .0
-                .get_mut((game_input.last_confirmed_frame as usize) % DESYNC_MAX_FRAMES)
-            {
-                assert!(
-                    frame_hash.frame != game_input.last_confirmed_frame
-                        || frame_hash.rapier_checksum == game_input.last_confirmed_hash,
-                    "Got new data for existing frame data {}",
-                    frame_hash.frame
-                );
-
-                // Only update this local data if the frame is new-to-us.
-                // We don't want to overwrite any existing validated status
-                // unless the frame is replacing what is already in the buffer.
-                if frame_hash.frame != game_input.last_confirmed_frame {
-                    frame_hash.frame = game_input.last_confirmed_frame;
-                    frame_hash.rapier_checksum = game_input.last_confirmed_hash;
-                    frame_hash.validated = false;
-                }
-            }
-        }
 
         // On to the boring stuff
         let input = match input_status {
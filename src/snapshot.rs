@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use bevy::ecs::world::World;
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::DEFAULT_CHECKSUM;
+use crate::prelude::*;
+
+/// A subsystem that can save and restore its own frame-accurate state as
+/// part of the rollback pipeline, without `save_rapier_context`/
+/// `rollback_rapier_context` needing to know anything about it.
+///
+/// [`RapierSnapshot`] is the built-in implementation; it replaces what used
+/// to be hardcoded directly into those two systems. Anything else that
+/// needs to survive a rollback (a particle system, an RNG seed, a
+/// scripted-event queue) can implement this trait and register an instance
+/// in [`RollbackSnapshots`] instead of touching the core systems.
+pub trait RollbackSnapshot: Send + Sync {
+    /// Serialize whatever state this subsystem needs to restore later.
+    /// Takes `&mut self` (not just `&self`) so an impl can keep bookkeeping
+    /// like a keyframe history in a plain field instead of needing interior
+    /// mutability, which would have to be thread-safe to satisfy this
+    /// trait's `Sync` bound.
+    fn save(&mut self, world: &World) -> Vec<u8>;
+
+    /// Restore this subsystem's state from bytes produced by `save`.
+    fn restore(&mut self, world: &mut World, bytes: &[u8]);
+
+    /// Checksum a previously-saved byte buffer. Mixed into the aggregate
+    /// [`PhysicsRollbackState::rapier_checksum`] used for desync detection.
+    /// Widened to 64 bits so two genuinely divergent states don't collide
+    /// into a false negative; see [`crate::checksum`].
+    fn checksum(&self, bytes: &[u8]) -> u64;
+}
+
+/// All subsystems participating in frame-accurate save/restore, in the
+/// order they are saved and restored.
+#[derive(Resource)]
+pub struct RollbackSnapshots(pub Vec<Box<dyn RollbackSnapshot>>);
+
+impl Default for RollbackSnapshots {
+    fn default() -> Self {
+        Self(vec![Box::new(RapierSnapshot::default())])
+    }
+}
+
+/// How often [`RapierSnapshot`] stores a full snapshot instead of a delta.
+/// Bounds how many frames a delta can lie from its base keyframe, and gives
+/// us a recovery point if a base frame ever falls out of history.
+const KEYFRAME_INTERVAL: i32 = 60;
+
+/// What [`RapierSnapshot::save`] actually hands back to the generic
+/// pipeline: either a full serialized `RapierContext`, or a byte-diff
+/// against `base_frame`'s full state.
+#[derive(Serialize, Deserialize)]
+enum SnapshotEntry {
+    Keyframe(Vec<u8>),
+    Delta { base_frame: Frame, delta: Vec<u8> },
+}
+
+/// The original hardcoded Rapier save/restore/checksum logic, lifted into a
+/// [`RollbackSnapshot`] impl so it participates in the same generic
+/// pipeline as any other registered snapshot.
+///
+/// Serializing the entire `RapierContext` with bincode every single frame
+/// dominates memory and bandwidth for large collider/body counts, so rather
+/// than handing the full blob to the pipeline every frame, this stores only
+/// a byte-diff against the nearest keyframe. Unlike a naive "diff against
+/// whatever we saved last" scheme, only actual keyframes (taken every
+/// `KEYFRAME_INTERVAL` frames, or whenever a diff wouldn't be smaller than
+/// the full state anyway) are kept around, so memory is bounded by how many
+/// keyframes fit in `KEYFRAME_INTERVAL + DESYNC_MAX_FRAMES` frames, not by
+/// one full snapshot per frame,
+/// and reconstruction never has to replay more than a single delta.
+#[derive(Default)]
+pub struct RapierSnapshot {
+    /// Full bytes of keyframes we've taken, keyed by frame. Deltas are
+    /// always diffed against one of these, never against another delta, so
+    /// reconstructing a delta is always a single `apply_diff` call. A plain
+    /// field now that `save` takes `&mut self`, instead of a `RefCell` —
+    /// which made this type `!Sync` and broke the trait's `Sync` bound.
+    keyframes: HashMap<Frame, Vec<u8>>,
+}
+
+impl RapierSnapshot {
+    fn nearest_keyframe(&self, frame: Frame) -> Option<(Frame, Vec<u8>)> {
+        self.keyframes
+            .iter()
+            .filter(|(&f, _)| f < frame)
+            .max_by_key(|(&f, _)| f)
+            .map(|(&f, bytes)| (f, bytes.clone()))
+    }
+
+    fn record_keyframe(&mut self, frame: Frame, bytes: Vec<u8>) {
+        self.keyframes.insert(frame, bytes);
+
+        // A keyframe can be the diff base for a delta up to KEYFRAME_INTERVAL
+        // frames later, and that delta frame can still be rolled back to for
+        // another DESYNC_MAX_FRAMES after that. Evicting by DESYNC_MAX_FRAMES
+        // measured from the keyframe itself, rather than from the last frame
+        // that could reference it, could drop the base out from under a
+        // delta the rollback window can still reach.
+        self.keyframes
+            .retain(|f, _| frame - *f < KEYFRAME_INTERVAL + DESYNC_MAX_FRAMES as i32);
+    }
+
+    fn reconstruct(&self, entry: SnapshotEntry) -> Option<Vec<u8>> {
+        match entry {
+            SnapshotEntry::Keyframe(bytes) => Some(bytes),
+            SnapshotEntry::Delta { base_frame, delta } => match self.keyframes.get(&base_frame) {
+                Some(base_bytes) => Some(apply_diff(base_bytes, &delta)),
+                None => {
+                    log::warn!(
+                        "Missing base frame {} for rollback delta, waiting for next keyframe",
+                        base_frame
+                    );
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl RollbackSnapshot for RapierSnapshot {
+    fn save(&mut self, world: &World) -> Vec<u8> {
+        let current_frame = world.resource::<CurrentFrame>().0;
+        let rapier = world.resource::<RapierContext>();
+        let full_bytes = bincode::serialize(rapier).unwrap_or_default();
+
+        let entry = if current_frame % KEYFRAME_INTERVAL == 0 {
+            self.record_keyframe(current_frame, full_bytes.clone());
+            SnapshotEntry::Keyframe(full_bytes)
+        } else {
+            match self
+                .nearest_keyframe(current_frame)
+                .and_then(|(base_frame, base_bytes)| {
+                    diff(&base_bytes, &full_bytes).map(|delta| (base_frame, delta))
+                }) {
+                Some((base_frame, delta)) if delta.len() < full_bytes.len() => {
+                    SnapshotEntry::Delta { base_frame, delta }
+                }
+                _ => {
+                    self.record_keyframe(current_frame, full_bytes.clone());
+                    SnapshotEntry::Keyframe(full_bytes)
+                }
+            }
+        };
+
+        bincode::serialize(&entry).unwrap_or_default()
+    }
+
+    fn restore(&mut self, world: &mut World, bytes: &[u8]) {
+        let Ok(entry) = bincode::deserialize::<SnapshotEntry>(bytes) else {
+            return;
+        };
+        let Some(full_bytes) = self.reconstruct(entry) else {
+            return;
+        };
+        let Ok(context) = bincode::deserialize::<RapierContext>(&full_bytes) else {
+            return;
+        };
+
+        // Inserting or replacing the resource directly seems to screw up
+        // some of the crate-only properties. So, we copy over each public
+        // property instead.
+        let mut rapier = world.resource_mut::<RapierContext>();
+        rapier.bodies = context.bodies;
+        rapier.broad_phase = context.broad_phase;
+        rapier.ccd_solver = context.ccd_solver;
+        rapier.colliders = context.colliders;
+        rapier.impulse_joints = context.impulse_joints;
+        rapier.integration_parameters = context.integration_parameters;
+        rapier.islands = context.islands;
+        rapier.multibody_joints = context.multibody_joints;
+        rapier.narrow_phase = context.narrow_phase;
+        rapier.query_pipeline = context.query_pipeline;
+
+        // pipeline is not serialized
+    }
+
+    fn checksum(&self, bytes: &[u8]) -> u64 {
+        // Desync detection must reflect the full reconstructed state, not
+        // just whatever a delta changed, so reuse the same reconstruction
+        // path as `restore`.
+        let Ok(entry) = bincode::deserialize::<SnapshotEntry>(bytes) else {
+            return 0;
+        };
+        match self.reconstruct(entry) {
+            Some(full_bytes) => DEFAULT_CHECKSUM(&full_bytes),
+            None => 0,
+        }
+    }
+}
+
+/// Wraps already-serialized full state as a [`SnapshotEntry::Keyframe`] so
+/// it can be handed to a [`RapierSnapshot::restore`] (or any consumer
+/// expecting `RollbackSnapshot`-shaped bytes) without needing a base frame
+/// to diff against. Used by the spectator streaming path, which re-derives
+/// full state directly rather than reusing whatever a host happened to
+/// save that frame, so a spectator never has to resolve a delta against
+/// history it doesn't have.
+pub(crate) fn encode_keyframe(full_bytes: Vec<u8>) -> Vec<u8> {
+    bincode::serialize(&SnapshotEntry::Keyframe(full_bytes)).unwrap_or_default()
+}
+
+/// Byte-level diff of two equal-length buffers: a flat list of
+/// `(offset, new_byte)` pairs for every position that changed. Falls back
+/// to `None` (caller should store a full keyframe instead) if the lengths
+/// differ, since there's nothing meaningful to diff position-by-position.
+fn diff(base: &[u8], current: &[u8]) -> Option<Vec<u8>> {
+    if base.len() != current.len() {
+        return None;
+    }
+
+    let mut out = Vec::new();
+    for (i, (b, c)) in base.iter().zip(current.iter()).enumerate() {
+        if b != c {
+            out.extend_from_slice(&(i as u32).to_le_bytes());
+            out.push(*c);
+        }
+    }
+    Some(out)
+}
+
+/// Inverse of [`diff`]: apply a list of `(offset, new_byte)` pairs on top
+/// of `base` to reconstruct `current`.
+fn apply_diff(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut result = base.to_vec();
+    for chunk in delta.chunks_exact(5) {
+        let offset = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+        if let Some(slot) = result.get_mut(offset) {
+            *slot = chunk[4];
+        }
+    }
+    result
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use bevy::ecs::world::Mut;
+use bevy_matchbox::prelude::PeerId;
+
+use crate::prelude::*;
+use crate::snapshot::{encode_keyframe, RollbackSnapshot, RollbackSnapshots};
+
+/// Set from a launch flag to start this client as a spectator against
+/// `host_peer` instead of joining as an active player. Wiring this up to
+/// `ggrs::SpectatorSession` happens alongside the rest of session setup.
+#[derive(Clone, Default, Resource)]
+pub struct SpectatorConfig {
+    pub host_peer: Option<PeerId>,
+    pub sync_mode: SpectatorSyncMode,
+}
+
+/// How a spectator keeps its physics state in sync with the match:
+/// - `Resimulate` replays confirmed inputs through `apply_inputs` and the
+///   normal Rapier step, identical to an active player.
+/// - `SnapshotStream` skips resimulation entirely and installs a full
+///   serialized `RapierContext` snapshot of the latest confirmed frame the
+///   host has sent; confirmed frames in between are not installed.
+///   Trades bandwidth for zero CPU spent observing.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Resource)]
+pub enum SpectatorSyncMode {
+    #[default]
+    Resimulate,
+    SnapshotStream,
+}
+
+/// Confirmed-frame snapshots received since the last
+/// [`apply_spectator_snapshots`], in arrival order. Only the last one is
+/// installed; the older ones are discarded.
+#[derive(Default, Resource)]
+pub struct SpectatorSnapshotQueue(pub Vec<(Frame, Vec<u8>)>);
+
+/// Installs the most recent confirmed-frame Rapier snapshot directly,
+/// bypassing resimulation. Reuses the registered [`RapierSnapshot`]'s
+/// [`RollbackSnapshot::restore`], the same per-field copy
+/// `rollback_rapier_context` uses during a normal rollback.
+///
+/// Only used when [`SpectatorConfig::sync_mode`] is
+/// [`SpectatorSyncMode::SnapshotStream`]. `queue_spectator_snapshot` always
+/// sends self-contained keyframe bytes rather than a delta, so this never
+/// has to resolve against history the spectator doesn't have.
+pub fn apply_spectator_snapshots(world: &mut World) {
+    let latest = {
+        let mut queue = world.resource_mut::<SpectatorSnapshotQueue>();
+        queue.0.drain(..).last()
+    };
+
+    let Some((frame, bytes)) = latest else {
+        return;
+    };
+
+    world.resource_scope(|world, mut snapshots: Mut<RollbackSnapshots>| {
+        if let Some(rapier_snapshot) = snapshots.0.first_mut() {
+            rapier_snapshot.restore(world, &bytes);
+        }
+    });
+    log::info!("Spectator installed confirmed snapshot for frame {}", frame);
+}
+
+/// Raw serialized `RapierContext` bytes for every frame simulated, keyed by
+/// frame number. `queue_spectator_snapshot` needs this because `CurrentFrame`
+/// runs ahead of `ConfirmedFrame` by the whole prediction window for almost
+/// the entire match — by the time a frame is confirmed, it's long since
+/// stopped being "current", so there's no tick where the two line up to
+/// read `RapierContext` directly off of. Recording bytes here as each frame
+/// is simulated means the bytes for whatever frame just got confirmed are
+/// still around when confirmation catches up to it.
+#[derive(Default, Resource)]
+pub struct SpectatorFrameHistory(HashMap<Frame, Vec<u8>>);
+
+impl SpectatorFrameHistory {
+    fn record(&mut self, frame: Frame, bytes: Vec<u8>) {
+        self.0.insert(frame, bytes);
+        self.0.retain(|f, _| frame - *f < DESYNC_MAX_FRAMES as i32);
+    }
+}
+
+pub fn record_spectator_frame_history(
+    current_frame: Res<CurrentFrame>,
+    rapier: Res<RapierContext>,
+    mut history: ResMut<SpectatorFrameHistory>,
+) {
+    let bytes = bincode::serialize(rapier.as_ref()).unwrap_or_default();
+    history.record(current_frame.0, bytes);
+}
+
+/// Host-side: once a frame is confirmed, queue a self-contained Rapier
+/// snapshot of it for any spectators running in
+/// [`SpectatorSyncMode::SnapshotStream`].
+#[derive(Default, Resource)]
+pub struct OutgoingSpectatorSnapshots(pub Vec<(Frame, Vec<u8>)>);
+
+/// Last confirmed frame we already queued a snapshot for, so
+/// `queue_spectator_snapshot` only fires once per newly confirmed frame
+/// rather than resending the same one every tick `ConfirmedFrame` doesn't
+/// move.
+#[derive(Default, Resource)]
+pub struct LastQueuedSpectatorFrame(Option<Frame>);
+
+pub fn queue_spectator_snapshot(
+    confirmed_frame: Res<ConfirmedFrame>,
+    history: Res<SpectatorFrameHistory>,
+    mut last_queued: ResMut<LastQueuedSpectatorFrame>,
+    mut outgoing: ResMut<OutgoingSpectatorSnapshots>,
+) {
+    if last_queued.0 == Some(confirmed_frame.0) {
+        return;
+    }
+
+    // The frame may have already fallen out of history if confirmation is
+    // lagging behind by more than DESYNC_MAX_FRAMES; nothing to send until
+    // a more recent frame is confirmed.
+    let Some(full_bytes) = history.0.get(&confirmed_frame.0) else {
+        return;
+    };
+
+    outgoing
+        .0
+        .push((confirmed_frame.0, encode_keyframe(full_bytes.clone())));
+    last_queued.0 = Some(confirmed_frame.0);
+}
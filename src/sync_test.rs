@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::prelude::*;
+
+/// Single-process determinism harness, mirroring ggrs's `SyncTestSession`.
+///
+/// Every frame we force a rollback of the last `check_distance` frames and
+/// re-simulate them through the normal rollback pipeline. Because
+/// `save_rapier_context` already records a checksum for every frame it sees,
+/// all this needs to do is keep the history around long enough to compare
+/// the resimulated checksum against the one we recorded the first time.
+#[derive(Resource)]
+pub struct SyncTestConfig {
+    pub check_distance: usize,
+}
+
+impl Default for SyncTestConfig {
+    fn default() -> Self {
+        Self { check_distance: 2 }
+    }
+}
+
+/// Checksums recorded for each frame we've saved, keyed by frame number.
+/// Analogous to `SyncTestSession::checksum_history` in ggrs.
+#[derive(Default, Resource)]
+pub struct ChecksumHistory(pub HashMap<Frame, u64>);
+
+impl ChecksumHistory {
+    /// Record a checksum for `frame`. If `frame` was already recorded once
+    /// — i.e. this is the same frame coming back around after
+    /// [`force_sync_test_rollback`] forced a resimulation of it — compare
+    /// the two checksums instead of just overwriting the old one, since
+    /// those are the only two values that can ever reveal a desync here:
+    /// the same frame simulated twice should always produce the same
+    /// state.
+    ///
+    /// Also evicts anything older than `DESYNC_MAX_FRAMES` so this can't
+    /// grow unbounded over a long match.
+    pub fn record(&mut self, frame: Frame, checksum: u64) {
+        if let Some(&first) = self.0.get(&frame) {
+            if first != checksum {
+                log::error!(
+                    "SyncTest desync detected: frame {} checksum {} on first simulation, {} on resimulation",
+                    frame,
+                    first,
+                    checksum
+                );
+            } else {
+                log::debug!(
+                    "SyncTest frame {} matched checksum {} after resimulation",
+                    frame,
+                    checksum
+                );
+            }
+        }
+
+        self.0.insert(frame, checksum);
+        self.0.retain(|f, _| frame - f < DESYNC_MAX_FRAMES as i32);
+    }
+}
+
+/// Seeded in place of `rand::thread_rng()` wherever gameplay logic needs
+/// randomness (currently just `rollback::input`'s idle-input behavior), so
+/// a match is reproducible from its seed instead of depending on OS
+/// entropy. Without this, SyncTest's checksum comparison would fail on
+/// every resimulated frame that happened to roll a different random input
+/// than the one it rolled the first time.
+#[derive(Resource)]
+pub struct SyncTestRng(pub StdRng);
+
+impl Default for SyncTestRng {
+    fn default() -> Self {
+        Self(StdRng::seed_from_u64(0))
+    }
+}
+
+/// Forces a rollback to `check_distance` frames ago on every frame, the way
+/// ggrs's `SyncTestSession` does, so the rollback pipeline actually
+/// resimulates recent frames instead of just running forward. Without this,
+/// [`ChecksumHistory::record`] would never see the same frame twice and
+/// could never detect a desync.
+pub fn force_sync_test_rollback(
+    sync_test: Res<SyncTestConfig>,
+    current_frame: Res<CurrentFrame>,
+    mut rollback_status: ResMut<RollbackStatus>,
+) {
+    // Frames younger than check_distance haven't happened yet, so there's
+    // nothing to roll back to.
+    if current_frame.0 < sync_test.check_distance as i32 {
+        return;
+    }
+
+    rollback_status.is_rollback = true;
+    rollback_status.rollback_frame = current_frame.0 - sync_test.check_distance as i32;
+}